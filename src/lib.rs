@@ -1,46 +1,175 @@
 use cassandra_protocol::compression::Compression;
 use cassandra_protocol::frame::message_query::BodyReqQuery;
 use cassandra_protocol::frame::message_response::ResponseBody;
-use cassandra_protocol::frame::message_result::{BodyResResultRows, ResResultBody};
+use cassandra_protocol::frame::message_result::{
+    BodyResResultPrepared, BodyResResultRows, PreparedMetadata, ResResultBody,
+};
 use cassandra_protocol::frame::Envelope;
+use cassandra_protocol::frame::ParseEnvelopeError;
 use cassandra_protocol::frame::Flags;
 use cassandra_protocol::frame::Opcode;
 use cassandra_protocol::frame::Version;
 use cassandra_protocol::query::query_params::QueryParams;
+use cassandra_protocol::query::QueryValues;
 use cassandra_protocol::types::cassandra_type::{wrapper_fn, CassandraType};
+use cassandra_protocol::types::value::Value;
+use cassandra_protocol::types::CBytes;
+use cassandra_protocol::types::CBytesShort;
+use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
 use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
 use rustls::{Certificate, CertificateError, RootCertStore, ServerName};
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio_tungstenite::tungstenite::error::Error;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::error::Error as WsError;
 use tokio_tungstenite::tungstenite::error::ProtocolError;
 use tokio_tungstenite::tungstenite::handshake::client::generate_key;
-use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::Connector;
 use tokio_tungstenite::WebSocketStream;
 
 pub struct Session {
-    in_rx: UnboundedReceiver<Message>,
+    in_rx: UnboundedReceiver<Result<Message>>,
     out_tx: UnboundedSender<Message>,
+    credentials: Option<Credentials>,
+    compression: Compression,
+}
+
+/// Convenience alias for results returned by this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors surfaced by a [`Session`].
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying WebSocket connection failed.
+    Connection(Box<WsError>),
+    /// The TLS layer rejected the connection.
+    Tls(rustls::Error),
+    /// A frame could not be encoded or decoded.
+    Protocol(Box<cassandra_protocol::error::Error>),
+    /// The server replied with an `ERROR` frame.
+    Server { message: String },
+    /// An opcode was received that is not valid for the current exchange.
+    UnexpectedOpcode(Opcode),
+    /// The connection task was shut down and its channel closed.
+    ChannelClosed,
+    /// The server demanded authentication but no [`Credentials`] were supplied.
+    MissingCredentials,
+    /// The connection address could not be parsed into a WebSocket request.
+    InvalidAddress(String),
+    /// A prepared statement was executed with the wrong number of bound values.
+    BoundValueCount { expected: usize, got: usize },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Connection(err) => write!(f, "websocket connection error: {err}"),
+            Error::Tls(err) => write!(f, "tls error: {err}"),
+            Error::Protocol(err) => write!(f, "protocol error: {err}"),
+            Error::Server { message } => write!(f, "server returned error: {message}"),
+            Error::UnexpectedOpcode(opcode) => write!(f, "unexpected opcode: {opcode:?}"),
+            Error::ChannelClosed => write!(f, "connection channel closed"),
+            Error::MissingCredentials => {
+                write!(f, "server requires authentication but no credentials were provided")
+            }
+            Error::InvalidAddress(address) => write!(f, "invalid connection address: {address}"),
+            Error::BoundValueCount { expected, got } => write!(
+                f,
+                "expected {expected} bound values for prepared statement but got {got}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<WsError> for Error {
+    fn from(err: WsError) -> Self {
+        Error::Connection(Box::new(err))
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Self {
+        Error::Tls(err)
+    }
+}
+
+impl From<cassandra_protocol::error::Error> for Error {
+    fn from(err: cassandra_protocol::error::Error) -> Self {
+        Error::Protocol(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Connection(Box::new(WsError::Io(err)))
+    }
+}
+
+impl From<ParseEnvelopeError> for Error {
+    fn from(err: ParseEnvelopeError) -> Self {
+        Error::Protocol(Box::new(cassandra_protocol::error::Error::General(
+            err.to_string(),
+        )))
+    }
+}
+
+/// Credentials used to answer a server `AUTHENTICATE` challenge via SASL.
+#[derive(Clone)]
+pub enum Credentials {
+    /// `PasswordAuthenticator` / SASL `PLAIN`: the token is `\0username\0password`.
+    Plain { username: String, password: String },
+    /// SASL `EXTERNAL`: send an empty token so the server derives the identity
+    /// from the presented TLS client certificate (mTLS auth).
+    External,
+}
+
+impl Credentials {
+    // Build the SASL initial-response token sent in the first `AUTH_RESPONSE`.
+    fn initial_token(&self) -> Vec<u8> {
+        match self {
+            Credentials::Plain { username, password } => {
+                let mut token = Vec::with_capacity(username.len() + password.len() + 2);
+                token.push(0);
+                token.extend_from_slice(username.as_bytes());
+                token.push(0);
+                token.extend_from_slice(password.as_bytes());
+                token
+            }
+            Credentials::External => Vec::new(),
+        }
+    }
+}
+
+/// Handle to a server-side prepared statement returned by [`Session::prepare`].
+pub struct PreparedStatement {
+    id: CBytesShort,
+    metadata: PreparedMetadata,
 }
 
 impl Session {
-    fn construct_request(uri: &str, use_subprotocol_header: bool) -> Request {
-        let uri = uri.parse::<http::Uri>().unwrap();
+    fn construct_request(uri: &str, use_subprotocol_header: bool) -> Result<Request> {
+        let invalid = || Error::InvalidAddress(uri.to_string());
 
-        let authority = uri.authority().unwrap().as_str();
+        let uri = uri.parse::<http::Uri>().map_err(|_| invalid())?;
+
+        let authority = uri.authority().ok_or_else(invalid)?.as_str();
         let host = authority
             .find('@')
             .map(|idx| authority.split_at(idx + 1).1)
-            .unwrap_or_else(|| authority);
+            .unwrap_or(authority);
 
         if host.is_empty() {
-            panic!("Empty host name");
+            return Err(invalid());
         }
 
         let mut builder = http::Request::builder()
@@ -52,34 +181,44 @@ impl Session {
             .header("Sec-WebSocket-Key", generate_key());
 
         if use_subprotocol_header {
-            builder = builder.header(
-                "Sec-WebSocket-Protocol",
-                "cql".parse::<http::HeaderValue>().unwrap(),
-            );
+            builder = builder.header("Sec-WebSocket-Protocol", "cql");
         }
-        builder.uri(uri).body(()).unwrap()
+        builder.uri(uri).body(()).map_err(|_| invalid())
     }
 
-    pub async fn new(address: &str, use_subprotocol_header: bool) -> Self {
-        let (ws_stream, _) = tokio_tungstenite::connect_async(Self::construct_request(
-            address,
-            use_subprotocol_header,
-        ))
-        .await
-        .unwrap();
-
-        let (in_tx, in_rx) = unbounded_channel::<Message>();
+    pub async fn new(
+        address: &str,
+        credentials: Option<Credentials>,
+        compression: Compression,
+        use_subprotocol_header: bool,
+    ) -> Result<Self> {
+        let (ws_stream, _) =
+            tokio_tungstenite::connect_async(Self::construct_request(address, use_subprotocol_header)?)
+                .await?;
+
+        let (in_tx, in_rx) = unbounded_channel::<Result<Message>>();
         let (out_tx, out_rx) = unbounded_channel::<Message>();
 
         spawn_read_write_tasks(ws_stream, in_tx, out_rx);
 
-        let mut session = Self { in_rx, out_tx };
-        session.startup().await;
-        session
+        let mut session = Self {
+            in_rx,
+            out_tx,
+            credentials,
+            compression,
+        };
+        session.startup().await?;
+        Ok(session)
     }
 
-    pub async fn new_tls(address: &str, ca_path: &str, use_subprotocol_header: bool) -> Self {
-        let root_cert_store = load_ca(ca_path);
+    pub async fn new_tls(
+        address: &str,
+        root_store: RootStore,
+        credentials: Option<Credentials>,
+        compression: Compression,
+        use_subprotocol_header: bool,
+    ) -> Result<Self> {
+        let root_cert_store = root_store.root_cert_store()?;
 
         let tls_client_config = rustls::ClientConfig::builder()
             .with_safe_defaults()
@@ -87,105 +226,258 @@ impl Session {
             .with_no_client_auth();
 
         let (ws_stream, _) = tokio_tungstenite::connect_async_tls_with_config(
-            Self::construct_request(address, use_subprotocol_header),
+            Self::construct_request(address, use_subprotocol_header)?,
             None,
             false,
             Some(Connector::Rustls(Arc::new(tls_client_config))),
         )
-        .await
-        .unwrap();
+        .await?;
 
-        let (in_tx, in_rx) = unbounded_channel::<Message>();
+        let (in_tx, in_rx) = unbounded_channel::<Result<Message>>();
         let (out_tx, out_rx) = unbounded_channel::<Message>();
 
         spawn_read_write_tasks(ws_stream, in_tx, out_rx);
 
-        let mut session = Self { in_rx, out_tx };
-        session.startup().await;
-        session
+        let mut session = Self {
+            in_rx,
+            out_tx,
+            credentials,
+            compression,
+        };
+        session.startup().await?;
+        Ok(session)
     }
 
-    async fn startup(&mut self) {
-        let envelope = Envelope::new_req_startup(None, Version::V4);
-        self.out_tx.send(Self::encode(envelope)).unwrap();
+    async fn startup(&mut self) -> Result<()> {
+        let envelope =
+            Envelope::new_req_startup(Self::compression_name(self.compression), Version::V4);
+        // STARTUP is always sent uncompressed; the negotiated algorithm only
+        // takes effect for the frames that follow.
+        self.send(envelope, Compression::None)?;
 
-        let envelope = Self::decode(self.in_rx.recv().await.unwrap());
+        let envelope = self.recv(Compression::None).await?;
 
         match envelope.opcode {
-            Opcode::Ready => println!("cql-ws: received: {:?}", envelope),
-            Opcode::Authenticate => {
-                todo!();
+            Opcode::Ready => Ok(()),
+            Opcode::Authenticate => self.authenticate().await,
+            Opcode::Error => Err(Self::server_error(envelope)),
+            opcode => Err(Error::UnexpectedOpcode(opcode)),
+        }
+    }
+
+    async fn authenticate(&mut self) -> Result<()> {
+        let credentials = self
+            .credentials
+            .clone()
+            .ok_or(Error::MissingCredentials)?;
+
+        // Auth frames, like STARTUP, are exchanged uncompressed.
+        // `CBytes::new` yields a zero-length `[bytes]` value for the EXTERNAL
+        // empty token (not a null), so the server derives identity from the cert.
+        let envelope =
+            Envelope::new_req_auth_response(CBytes::new(credentials.initial_token()), Version::V4);
+        self.send(envelope, Compression::None)?;
+
+        // PLAIN and EXTERNAL are single round-trips, but honour any further
+        // challenges the authenticator sends by replaying the initial token.
+        loop {
+            let envelope = self.recv(Compression::None).await?;
+
+            match envelope.opcode {
+                Opcode::AuthChallenge => {
+                    let envelope = Envelope::new_req_auth_response(
+                        CBytes::new(credentials.initial_token()),
+                        Version::V4,
+                    );
+                    self.send(envelope, Compression::None)?;
+                }
+                Opcode::AuthSuccess => return Ok(()),
+                Opcode::Error => return Err(Self::server_error(envelope)),
+                opcode => return Err(Error::UnexpectedOpcode(opcode)),
             }
-            _ => panic!("expected to receive a ready or authenticate message"),
         }
     }
 
-    pub async fn query(&mut self, query: &str) -> Vec<Vec<CassandraType>> {
+    pub async fn query(&mut self, query: &str) -> Result<Vec<Vec<CassandraType>>> {
         let envelope = Envelope::new_query(
             BodyReqQuery {
                 query: query.into(),
                 query_params: QueryParams::default(),
             },
-            Flags::empty(),
+            Self::outgoing_flags(self.compression),
+            Version::V4,
+        );
+
+        self.send(envelope, self.compression)?;
+
+        let envelope = self.recv(self.compression).await?;
+        Self::decode_rows(envelope)
+    }
+
+    /// Prepare `cql` on the server, returning a handle carrying the statement id
+    /// and the bound-variable metadata needed to execute it later.
+    pub async fn prepare(&mut self, cql: &str) -> Result<PreparedStatement> {
+        let envelope = Envelope::new_req_prepare(
+            cql.into(),
+            None,
+            Self::outgoing_flags(self.compression),
+            Version::V4,
+        );
+
+        self.send(envelope, self.compression)?;
+
+        let envelope = self.recv(self.compression).await?;
+
+        if envelope.opcode == Opcode::Error {
+            return Err(Self::server_error(envelope));
+        }
+
+        if let ResponseBody::Result(ResResultBody::Prepared(BodyResResultPrepared {
+            id,
+            metadata,
+            ..
+        })) = envelope.response_body()?
+        {
+            Ok(PreparedStatement { id, metadata })
+        } else {
+            Err(Error::UnexpectedOpcode(envelope.opcode))
+        }
+    }
+
+    /// Execute a previously [`prepare`](Self::prepare)d statement, binding
+    /// `values` positionally against its metadata's `col_specs`.
+    pub async fn execute(
+        &mut self,
+        prepared: &PreparedStatement,
+        values: Vec<Value>,
+    ) -> Result<Vec<Vec<CassandraType>>> {
+        if values.len() != prepared.metadata.col_specs.len() {
+            return Err(Error::BoundValueCount {
+                expected: prepared.metadata.col_specs.len(),
+                got: values.len(),
+            });
+        }
+
+        let query_params = QueryParams {
+            values: Some(QueryValues::SimpleValues(values)),
+            ..Default::default()
+        };
+
+        let envelope = Envelope::new_req_execute(
+            &prepared.id,
+            None,
+            &query_params,
+            Self::outgoing_flags(self.compression),
             Version::V4,
         );
 
-        self.out_tx.send(Self::encode(envelope)).unwrap();
+        self.send(envelope, self.compression)?;
+
+        let envelope = self.recv(self.compression).await?;
+        Self::decode_rows(envelope)
+    }
 
-        let envelope = Self::decode(self.in_rx.recv().await.unwrap());
+    // Decode a `RESULT Rows` envelope into wrapped Cassandra values. Shared by
+    // `query` and `execute`; an `ERROR` frame is surfaced as a typed error.
+    fn decode_rows(envelope: Envelope) -> Result<Vec<Vec<CassandraType>>> {
+        if envelope.opcode == Opcode::Error {
+            return Err(Self::server_error(envelope));
+        }
 
         if let ResponseBody::Result(ResResultBody::Rows(BodyResResultRows {
             rows_content,
             metadata,
             ..
-        })) = envelope.response_body().unwrap()
+        })) = envelope.response_body()?
         {
             let mut result_values = vec![];
             for row in &rows_content {
                 let mut row_result_values = vec![];
                 for (i, col_spec) in metadata.col_specs.iter().enumerate() {
                     let wrapper = wrapper_fn(&col_spec.col_type.id);
-                    let value = wrapper(&row[i], &col_spec.col_type, envelope.version).unwrap();
+                    let value = wrapper(&row[i], &col_spec.col_type, envelope.version)?;
 
                     row_result_values.push(value);
                 }
                 result_values.push(row_result_values);
             }
 
-            result_values
+            Ok(result_values)
         } else {
-            panic!("unexpected to recieve a result envelope");
+            Err(Error::UnexpectedOpcode(envelope.opcode))
         }
     }
 
-    fn encode(envelope: Envelope) -> Message {
-        let data = envelope.encode_with(Compression::None).unwrap();
-        Message::Binary(data)
+    // Turn a server `ERROR` envelope into a typed `Error::Server`.
+    fn server_error(envelope: Envelope) -> Error {
+        match envelope.response_body() {
+            Ok(ResponseBody::Error(err)) => Error::Server {
+                message: err.message,
+            },
+            Ok(_) => Error::UnexpectedOpcode(envelope.opcode),
+            Err(err) => Error::Protocol(Box::new(err)),
+        }
     }
 
-    fn decode(ws_message: Message) -> Envelope {
+    // Send an envelope to the write task, reporting a closed channel as an error.
+    fn send(&self, envelope: Envelope, compression: Compression) -> Result<()> {
+        self.out_tx
+            .send(Self::encode(envelope, compression)?)
+            .map_err(|_| Error::ChannelClosed)
+    }
+
+    // Await the next envelope from the read task, propagating connection errors.
+    async fn recv(&mut self, compression: Compression) -> Result<Envelope> {
+        let message = self.in_rx.recv().await.ok_or(Error::ChannelClosed)??;
+        Self::decode(message, compression)
+    }
+
+    // Map the negotiated algorithm to the STARTUP options value, or `None` when
+    // no compression is in use.
+    fn compression_name(compression: Compression) -> Option<String> {
+        match compression {
+            Compression::Lz4 => Some("lz4".to_string()),
+            Compression::Snappy => Some("snappy".to_string()),
+            Compression::None => None,
+        }
+    }
+
+    // Flags for an outgoing (non-STARTUP) envelope given the negotiated algorithm.
+    fn outgoing_flags(compression: Compression) -> Flags {
+        match compression {
+            Compression::None => Flags::empty(),
+            _ => Flags::COMPRESSION,
+        }
+    }
+
+    fn encode(envelope: Envelope, compression: Compression) -> Result<Message> {
+        let data = envelope.encode_with(compression)?;
+        Ok(Message::Binary(data))
+    }
+
+    fn decode(ws_message: Message, compression: Compression) -> Result<Envelope> {
         match ws_message {
-            Message::Binary(data) => {
-                Envelope::from_buffer(data.as_slice(), Compression::None)
-                    .unwrap()
-                    .envelope
-            }
-            _ => panic!("expected to receive a binary message"),
+            Message::Binary(data) => Ok(Envelope::from_buffer(data.as_slice(), compression)?
+                .envelope),
+            _ => Err(Error::Connection(Box::new(WsError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "expected to receive a binary message",
+            ))))),
         }
     }
 
-    pub async fn send_raw_ws_message(&mut self, ws_message: Message) {
-        self.out_tx.send(ws_message).unwrap();
+    pub async fn send_raw_ws_message(&mut self, ws_message: Message) -> Result<()> {
+        self.out_tx.send(ws_message).map_err(|_| Error::ChannelClosed)
     }
 
-    pub async fn wait_for_raw_ws_message_resp(&mut self) -> Message {
-        self.in_rx.recv().await.unwrap()
+    pub async fn wait_for_raw_ws_message_resp(&mut self) -> Result<Message> {
+        self.in_rx.recv().await.ok_or(Error::ChannelClosed)?
     }
 }
 
 fn spawn_read_write_tasks<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
     ws_stream: WebSocketStream<S>,
-    in_tx: UnboundedSender<Message>,
+    in_tx: UnboundedSender<Result<Message>>,
     mut out_rx: UnboundedReceiver<Message>,
 ) {
     let (mut write, mut read) = ws_stream.split();
@@ -198,13 +490,28 @@ fn spawn_read_write_tasks<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
                     if let Some(message) = result {
                         match message {
                             Ok(ws_message @ Message::Binary(_)) => {
-                                in_tx.send(ws_message).unwrap();
+                                // A closed receiver simply means the session was
+                                // dropped; stop reading rather than panicking.
+                                if in_tx.send(Ok(ws_message)).is_err() {
+                                    return;
+                                }
                             }
                             Ok(Message::Close(_)) => {
                                 return;
                             }
-                            Ok(_) => panic!("expected to recieve a binary message"),
-                            Err(err) => panic!("{err}")
+                            Ok(_) => {
+                                let _ = in_tx.send(Err(Error::Connection(Box::new(
+                                    WsError::Io(std::io::Error::new(
+                                        std::io::ErrorKind::InvalidData,
+                                        "expected to receive a binary message",
+                                    )),
+                                ))));
+                                return;
+                            }
+                            Err(err) => {
+                                let _ = in_tx.send(Err(Error::Connection(Box::new(err))));
+                                return;
+                            }
                         }
                     }
                 }
@@ -219,12 +526,15 @@ fn spawn_read_write_tasks<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
     tokio::spawn(async move {
         loop {
             if let Some(ws_message) = out_rx.recv().await {
-                write.send(ws_message).await.unwrap();
+                if write.send(ws_message).await.is_err() {
+                    // The peer is gone; nothing more we can do from here.
+                    return;
+                }
             } else {
                 match write.send(Message::Close(None)).await {
                     Ok(_) => {}
-                    Err(Error::Protocol(ProtocolError::SendAfterClosing)) => {}
-                    Err(err) => panic!("{err}"),
+                    Err(WsError::Protocol(ProtocolError::SendAfterClosing)) => {}
+                    Err(_) => {}
                 }
                 break;
             }
@@ -232,15 +542,58 @@ fn spawn_read_write_tasks<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
     });
 }
 
-fn load_ca(path: &str) -> RootCertStore {
-    let mut pem = BufReader::new(File::open(path).unwrap());
-    let certs = rustls_pemfile::certs(&mut pem).unwrap();
+/// Source of trusted CA roots used to build the TLS [`RootCertStore`].
+pub enum RootStore {
+    /// Load the platform's native trust store via `rustls-native-certs`.
+    #[cfg(feature = "rustls-native-certs")]
+    NativeCerts,
+    /// Use the Mozilla root bundle shipped by `webpki-roots`.
+    #[cfg(feature = "webpki-roots")]
+    WebpkiRoots,
+    /// Read an explicit PEM file of CA certificates.
+    Pem(String),
+}
+
+impl RootStore {
+    // Single entry point that turns the selected source into a `RootCertStore`.
+    fn root_cert_store(&self) -> Result<RootCertStore> {
+        match self {
+            #[cfg(feature = "rustls-native-certs")]
+            RootStore::NativeCerts => {
+                let mut root_cert_store = RootCertStore::empty();
+                for cert in rustls_native_certs::load_native_certs()? {
+                    root_cert_store.add(&Certificate(cert.0))?;
+                }
+                Ok(root_cert_store)
+            }
+            #[cfg(feature = "webpki-roots")]
+            RootStore::WebpkiRoots => {
+                let mut root_cert_store = RootCertStore::empty();
+                root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(
+                    |ta| {
+                        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                            ta.subject,
+                            ta.spki,
+                            ta.name_constraints,
+                        )
+                    },
+                ));
+                Ok(root_cert_store)
+            }
+            RootStore::Pem(path) => load_ca(path),
+        }
+    }
+}
+
+fn load_ca(path: &str) -> Result<RootCertStore> {
+    let mut pem = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut pem)?;
 
     let mut root_cert_store = RootCertStore::empty();
     for cert in certs {
-        root_cert_store.add(&Certificate(cert)).unwrap();
+        root_cert_store.add(&Certificate(cert))?;
     }
-    root_cert_store
+    Ok(root_cert_store)
 }
 
 pub struct SkipVerifyHostName {
@@ -285,3 +638,192 @@ impl ServerCertVerifier for SkipVerifyHostName {
         }
     }
 }
+
+pub struct PinPublicKey {
+    verifier: WebPkiVerifier,
+    pins: Vec<String>,
+}
+
+impl PinPublicKey {
+    /// Pin one or more base64-encoded SHA-256 digests of the server's
+    /// SubjectPublicKeyInfo. A connection is accepted only if the presented
+    /// end-entity certificate's public key hashes to one of these pins.
+    pub fn new(roots: RootCertStore, pins: Vec<String>) -> Self {
+        PinPublicKey {
+            verifier: WebPkiVerifier::new(roots, None),
+            pins,
+        }
+    }
+}
+
+// Like SkipVerifyHostName this tolerates a name mismatch (so a single cert can
+// be shared across cluster instances addressed by ip), but instead of blindly
+// accepting it we pin the server's public key. Normal chain/expiry validation
+// still runs first; we then hash the end-entity SubjectPublicKeyInfo and require
+// it to match one of the configured pins. This is the same "hash the public key
+// and compare" check used by the POSH pinning tooling.
+impl ServerCertVerifier for PinPublicKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
+        match self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            Ok(_) => {}
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {}
+            Err(err) => return Err(err),
+        }
+
+        use x509_parser::prelude::FromDer;
+        let (_, cert) = x509_parser::prelude::X509Certificate::from_der(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let digest = Sha256::digest(cert.public_key().raw);
+        let pin = base64::engine::general_purpose::STANDARD.encode(digest);
+
+        if self.pins.iter().any(|configured| configured == &pin) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server public key pin {pin} did not match any configured pin"
+            )))
+        }
+    }
+}
+
+/// An accepted server-side WebSocket connection, exposing the same binary
+/// envelope read/write plumbing a [`Session`] uses on the client side.
+pub struct Connection {
+    in_rx: UnboundedReceiver<Result<Message>>,
+    out_tx: UnboundedSender<Message>,
+}
+
+impl Connection {
+    /// Await the next binary message from the peer.
+    pub async fn recv(&mut self) -> Result<Message> {
+        self.in_rx.recv().await.ok_or(Error::ChannelClosed)?
+    }
+
+    /// Send a binary message back to the peer.
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        self.out_tx.send(message).map_err(|_| Error::ChannelClosed)
+    }
+}
+
+/// Accepts incoming CQL-over-WebSocket connections, optionally wrapped in TLS,
+/// and hands each one to a per-connection handler.
+pub struct Server {
+    address: String,
+    tls_acceptor: Option<TlsAcceptor>,
+}
+
+impl Server {
+    /// Start configuring a server bound to `address` (e.g. `"127.0.0.1:9999"`).
+    pub fn bind(address: impl Into<String>) -> Self {
+        Server {
+            address: address.into(),
+            tls_acceptor: None,
+        }
+    }
+
+    /// Terminate TLS on accepted connections with the given acceptor.
+    pub fn tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+
+    /// Run the accept loop, spawning a task per connection that invokes
+    /// `handler` with the negotiated [`Connection`].
+    pub async fn handler<H, Fut>(self, handler: H) -> Result<()>
+    where
+        H: Fn(Connection) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+    {
+        let listener = TcpListener::bind(&self.address).await?;
+        let handler = Arc::new(handler);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let handler = handler.clone();
+            let tls_acceptor = self.tls_acceptor.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = accept_connection(stream, tls_acceptor, handler).await {
+                    eprintln!("cql-ws: connection error: {err}");
+                }
+            });
+        }
+    }
+}
+
+// Plain-vs-TLS switch: wrap the stream in TLS when an acceptor is configured,
+// otherwise serve the raw TCP stream.
+async fn accept_connection<H, Fut>(
+    stream: TcpStream,
+    tls_acceptor: Option<TlsAcceptor>,
+    handler: Arc<H>,
+) -> Result<()>
+where
+    H: Fn(Connection) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    match tls_acceptor {
+        Some(tls_acceptor) => {
+            let tls_stream = tls_acceptor.accept(stream).await?;
+            serve_connection(tls_stream, handler).await
+        }
+        None => serve_connection(stream, handler).await,
+    }
+}
+
+async fn serve_connection<S, H, Fut>(stream: S, handler: Arc<H>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: Fn(Connection) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    let ws_stream = tokio_tungstenite::accept_hdr_async(stream, negotiate_cql).await?;
+
+    let (in_tx, in_rx) = unbounded_channel::<Result<Message>>();
+    let (out_tx, out_rx) = unbounded_channel::<Message>();
+
+    spawn_read_write_tasks(ws_stream, in_tx, out_rx);
+
+    handler(Connection { in_rx, out_tx }).await
+}
+
+// Accept every upgrade; when the client offers the `cql` subprotocol, echo it
+// back so the negotiated protocol is `cql`. Clients that offer no subprotocol
+// (or a different one) are accepted without a negotiated subprotocol.
+// The `Err` type is tungstenite's `ErrorResponse`, whose size we do not control.
+#[allow(clippy::result_large_err)]
+fn negotiate_cql(
+    request: &Request,
+    mut response: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    let offers_cql = request
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .map(|protocols| protocols.split(',').any(|protocol| protocol.trim() == "cql"))
+        .unwrap_or(false);
+
+    if offers_cql {
+        response.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            http::HeaderValue::from_static("cql"),
+        );
+    }
+
+    Ok(response)
+}